@@ -2,28 +2,136 @@ use std::path::{Path, PathBuf};
 use std::env;
 use std::collections::HashMap;
 use std::process::{exit, Command, Stdio};
+use std::os::unix::process::CommandExt;
 use std::iter;
 use std::io::{self, Write};
 
 use freedesktop_entry_parser::parse_entry;
 use clap;
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug)]
 struct ApplicationBody {
     path: PathBuf,
+    // raw, un-expanded Exec tokens: field codes (%f, %i, ...) are kept
+    // as-is so the entry can later be launched with or without targets
     exec: Vec<String>,
+    icon: Option<String>,
     term: bool,
+    mime_types: Vec<String>,
+    working_dir: Option<PathBuf>,
+    // Desktop Action sub-bodies, keyed by the action's own localized
+    // Name, e.g. ("New Window", ..); never nested further
+    actions: Vec<(String, ApplicationBody)>,
 }
 
 impl ApplicationBody {
-    fn new<P: Into<PathBuf>,E: IntoIterator<Item = String>>(path: P, exec: E, term: bool) -> ApplicationBody {
-        ApplicationBody {
-            path: path.into(),
-            exec: exec.into_iter().collect(),
-            term,
+    // expands field codes in `exec` against `name` (for %c) and `targets`
+    // (for %f/%u/%F/%U), the files/URLs being opened, empty if none
+    fn expanded_exec<S: AsRef<str>>(&self, name: &str, targets: &[S]) -> Vec<String> {
+        expand_field_codes(&self.exec, name, self.icon.as_deref(), &self.path, targets)
+    }
+}
+
+// tokenizes an Exec= value per the Desktop Entry spec: whitespace
+// separates arguments, except inside double quotes, where `\\`, `\"`,
+// `` \` `` and `\$` are unescaped and whitespace is kept literal
+fn tokenize_exec<S: AsRef<str>>(execstr: S) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut in_quotes = false;
+    let mut chars = execstr.as_ref().chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c == '\\' {
+                match chars.peek() {
+                    Some('\\') => { current.push('\\'); chars.next(); },
+                    Some('"') => { current.push('"'); chars.next(); },
+                    Some('`') => { current.push('`'); chars.next(); },
+                    Some('$') => { current.push('$'); chars.next(); },
+                    _ => current.push('\\'),
+                }
+            } else if c == '"' {
+                in_quotes = false;
+            } else {
+                current.push(c);
+            }
+        } else if c == '"' {
+            in_quotes = true;
+            in_token = true;
+        } else if c.is_whitespace() {
+            if in_token {
+                tokens.push(std::mem::take(&mut current));
+                in_token = false;
+            }
+        } else {
+            current.push(c);
+            in_token = true;
         }
     }
+    if in_token {
+        tokens.push(current);
+    }
+
+    tokens
+}
+
+// substitutes a single-value field code (%f, %u, %c, %k) inline within a
+// token, e.g. `--url=%u` -> `--url=file:///tmp/x`
+fn expand_inline_codes(tok: &str, name: &str, desktop_path: &Path, targets: &[&str]) -> String {
+    let mut out = String::new();
+    let mut chars = tok.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.peek() {
+            Some('%') => { out.push('%'); chars.next(); },
+            Some('f') | Some('u') => { out.push_str(targets.first().copied().unwrap_or("")); chars.next(); },
+            Some('F') | Some('U') => { out.push_str(&targets.join(" ")); chars.next(); },
+            Some('c') => { out.push_str(name); chars.next(); },
+            Some('k') => { out.push_str(&desktop_path.to_string_lossy()); chars.next(); },
+            _ => out.push('%'),
+        }
+    }
+
+    out
+}
+
+// expands Exec field codes, consuming whole tokens for the codes that
+// expand to zero or more arguments (%f %u %F %U %i) and substituting
+// %f %u %F %U %c %k inline wherever else they appear within a token
+fn expand_field_codes<S: AsRef<str>>(tokens: &[String], name: &str, icon: Option<&str>, desktop_path: &Path, targets: &[S]) -> Vec<String> {
+    let mut out = Vec::new();
+    let targets: Vec<&str> = targets.iter().map(|t| t.as_ref()).collect();
+
+    for tok in tokens {
+        match tok.as_str() {
+            "%f" | "%u" => {
+                if let Some(t) = targets.first() {
+                    out.push(t.to_string());
+                }
+            },
+            "%F" | "%U" => out.extend(targets.iter().map(|t| t.to_string())),
+            "%i" => {
+                if let Some(icon) = icon {
+                    out.push("--icon".to_string());
+                    out.push(icon.to_string());
+                }
+            },
+            "%d" | "%D" | "%n" | "%N" | "%v" | "%m" => (), // deprecated, silently dropped
+            _ if tok.contains('%') => out.push(expand_inline_codes(tok, name, desktop_path, &targets)),
+            _ => out.push(tok.clone()),
+        }
+    }
+
+    out
 }
 
 #[derive(Debug)]
@@ -33,29 +141,29 @@ struct Application {
 }
 
 impl Application {
-    fn new<P: Into<PathBuf>, N: Into<String>, E: IntoIterator<Item = String>>(name: N, path: P, exec: E, term: bool) -> Application {
-        Application {
-            name: name.into(),
-            body: ApplicationBody::new(path, exec, term),
-        }
-    }
-
-    fn exec_from_str<S: AsRef<str>>(execstr: S) -> Vec<String> {
-        execstr.as_ref()
-            .trim()
-            .split_whitespace()
-            .filter(|&s| !s.starts_with("%"))
-            .map(String::from)
-            .collect()
-    }
-
     fn from_file<P: AsRef<Path>>(path: P) -> Option<Application> {
         let desktop_file = parse_entry(path.as_ref()).ok()?;
 
         if desktop_file.section("Desktop Entry").attr("NoDisplay") != Some("true") { // check if visible
             if desktop_file.section("Desktop Entry").attr("Type") == Some("Application") { // check if app
+                // a TryExec= binary that isn't on PATH makes the entry non-launchable
+                if let Some(try_exec) = desktop_file.section("Desktop Entry").attr("TryExec") {
+                    if !binary_on_path(try_exec) {
+                        return None;
+                    }
+                }
+
                 let name_o = desktop_file.section("Desktop Entry").attr("Name");
                 let execstr_o = desktop_file.section("Desktop Entry").attr("Exec");
+                let icon = desktop_file.section("Desktop Entry").attr("Icon").map(String::from);
+                let working_dir = desktop_file.section("Desktop Entry").attr("Path").map(PathBuf::from);
+                let mime_types = desktop_file.section("Desktop Entry")
+                    .attr("MimeType")
+                    .unwrap_or("")
+                    .split(';')
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+                    .collect();
                 let term: bool = desktop_file.section("Desktop Entry")
                     .attr("Terminal")
                     .unwrap_or("false")
@@ -63,45 +171,445 @@ impl Application {
                     .parse()
                     .ok()?;
 
+                let actions = desktop_file.section("Desktop Entry")
+                    .attr("Actions")
+                    .unwrap_or("")
+                    .split(';')
+                    .filter(|s| !s.is_empty())
+                    .filter_map(|action_id| {
+                        let section = desktop_file.section(format!("Desktop Action {}", action_id));
+                        let action_name = section.attr("Name")?;
+                        let action_exec = section.attr("Exec")?;
+                        let action_icon = section.attr("Icon").map(String::from).or_else(|| icon.clone());
+                        let action_term = section.attr("Terminal")
+                            .map(|t| t.to_lowercase() == "true")
+                            .unwrap_or(term);
+
+                        Some((action_name.to_string(), ApplicationBody {
+                            path: path.as_ref().to_path_buf(),
+                            exec: tokenize_exec(action_exec),
+                            icon: action_icon,
+                            term: action_term,
+                            mime_types: Vec::new(),
+                            working_dir: working_dir.clone(),
+                            actions: Vec::new(),
+                        }))
+                    })
+                    .collect();
+
                 match (name_o, execstr_o) {
                     (Some(name), Some(execstr)) => {
-                        return Some(Application::new(
-                                name,
-                                path.as_ref(),
-                                Application::exec_from_str(execstr),
-                                term
-                                ));
+                        return Some(Application {
+                            name: name.to_string(),
+                            body: ApplicationBody {
+                                path: path.as_ref().to_path_buf(),
+                                exec: tokenize_exec(execstr),
+                                icon,
+                                term,
+                                mime_types,
+                                working_dir,
+                                actions,
+                            },
+                        });
                     },
                     _ => ()
                 }
-            } 
+            }
         }
         None
     }
 }
 
-fn is_desktop_file<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
-    if path.as_ref()
+// true if `bin` (a path or a bare binary name) names an existing file:
+// an absolute/relative path is checked directly, a bare name is looked
+// up on $PATH; the executable bit is not checked
+fn binary_on_path<S: AsRef<str>>(bin: S) -> bool {
+    let bin = bin.as_ref();
+
+    if bin.contains('/') {
+        return Path::new(bin).is_file();
+    }
+
+    env::var_os("PATH").is_some_and(|paths| {
+        env::split_paths(&paths).any(|dir| dir.join(bin).is_file())
+    })
+}
+
+fn is_desktop_file<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
         .to_str()
         .unwrap()
-        .ends_with("desktop") { 
-            if let Ok(location) = path.as_ref().canonicalize() {
-                if location.is_file() {
-                    return Some(location)
-                }
-            }
+        .ends_with("desktop")
+        && path.as_ref().is_file()
+}
+
+// walks `dir` recursively, collecting every `.desktop` file found; paths
+// are kept exactly as walked (not canonicalized) so they stay relative
+// to `dir`, which `desktop_file_id` relies on to derive an ID even when
+// a file is reached through a symlinked subdirectory
+fn find_desktop_files<P: AsRef<Path>>(dir: P, out: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir.as_ref()) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.is_dir() {
+            find_desktop_files(&path, out);
+        } else if is_desktop_file(&path) {
+            out.push(path);
+        }
     }
-    None
 }
 
-fn get_desktop_apps<T: AsRef<Path>>(path: T) -> io::Result<Vec<Application>> {
-    Ok(std::fs::read_dir(path.as_ref())?
-        .filter_map(|i| if let Ok(s) = i {Some(s.path())} else {None}) // Result<DirEntry> -> PathBuf
-        .filter_map(is_desktop_file)
-        .filter_map(Application::from_file)
+// the desktop-file ID is the file's path relative to the `applications/`
+// root with '/' replaced by '-' and the `.desktop` suffix stripped, e.g.
+// `kde/foo.desktop` -> `kde-foo`; `path` is taken as walked (already
+// relative to `root`), so a symlinked subdirectory that canonicalizes
+// outside of `root` doesn't make the app unreachable
+fn desktop_file_id(root: &Path, path: &Path) -> Option<String> {
+    let rel = path.strip_prefix(root).ok()?;
+    let id = rel.to_str()?
+        .replace(std::path::MAIN_SEPARATOR, "-");
+    Some(id.trim_end_matches(".desktop").to_string())
+}
+
+// searches `root` for desktop files and returns them keyed by desktop-file
+// ID, ready to be merged with entries from lower-priority directories
+fn get_desktop_apps<T: AsRef<Path>>(root: T) -> io::Result<Vec<(String, Application)>> {
+    let root = root.as_ref();
+    let mut files = Vec::new();
+    find_desktop_files(root, &mut files);
+
+    Ok(files.into_iter()
+        .filter_map(|path| {
+            let id = desktop_file_id(root, &path)?;
+            let app = Application::from_file(&path)?;
+            Some((id, app))
+        })
+        .flat_map(|(id, app)| flatten_actions(id, app))
         .collect())
 }
 
+// expands an Application's Desktop Actions into standalone menu entries
+// ("AppName — ActionName"), alongside the primary entry itself
+fn flatten_actions(id: String, mut app: Application) -> Vec<(String, Application)> {
+    let actions = std::mem::take(&mut app.body.actions);
+    let parent_name = app.name.clone();
+
+    let mut entries = Vec::with_capacity(1 + actions.len());
+    entries.push((id.clone(), app));
+
+    for (i, (action_name, action_body)) in actions.into_iter().enumerate() {
+        let action_id = format!("{}::{}", id, i);
+        let action_app = Application {
+            name: format!("{} — {}", parent_name, action_name),
+            body: action_body,
+        };
+        entries.push((action_id, action_app));
+    }
+
+    entries
+}
+
+// XDG Base Directory applications dirs, in priority order: $XDG_DATA_HOME
+// first, then each entry of $XDG_DATA_DIRS
+fn xdg_applications_dirs() -> Vec<PathBuf> {
+    let data_home = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            let mut h = PathBuf::from(env::var("HOME").unwrap());
+            h.push(".local");
+            h.push("share");
+            h
+        });
+
+    let data_dirs = env::var("XDG_DATA_DIRS")
+        .unwrap_or_else(|_| "/usr/local/share:/usr/share".to_string());
+
+    iter::once(data_home)
+        .chain(env::split_paths(&data_dirs))
+        .map(|mut dir| {
+            dir.push("applications");
+            dir
+        })
+        .collect()
+}
+
+// merges desktop-file repos in priority order: an entry from an
+// earlier repo hides any same-ID entry from a later one
+fn merge_apps(repos: Vec<io::Result<Vec<(String, Application)>>>) -> HashMap<String, Application> {
+    let mut apps: HashMap<String, Application> = HashMap::new();
+
+    for repo in repos.into_iter().filter_map(Result::ok) {
+        // a desktop file's action entries ("<id>::<n>") go with its ID
+        let claimed: std::collections::HashSet<String> = apps.keys()
+            .map(|id| id.split("::").next().unwrap_or(id).to_string())
+            .collect();
+        for (id, app) in repo {
+            if !claimed.contains(id.split("::").next().unwrap_or(&id)) {
+                apps.entry(id).or_insert(app);
+            }
+        }
+    }
+
+    apps
+}
+
+// the default-association chain, merged from every mimeapps.list the
+// spec says to consult, highest priority first
+#[derive(Debug, Default)]
+struct MimeAssociations {
+    default: HashMap<String, Vec<String>>,
+    added: HashMap<String, Vec<String>>,
+    removed: HashMap<String, Vec<String>>,
+}
+
+impl MimeAssociations {
+    // the desktop-file IDs that should handle `mime`, defaults first,
+    // then any added association, with removed associations filtered out
+    fn handlers_for(&self, mime: &str) -> Vec<String> {
+        let removed = self.removed.get(mime);
+        self.default.get(mime).into_iter().flatten()
+            .chain(self.added.get(mime).into_iter().flatten())
+            .filter(|id| !removed.is_some_and(|r| r.contains(*id)))
+            .cloned()
+            .unique()
+            .collect()
+    }
+}
+
+// mimeapps.list files in priority order: $XDG_CONFIG_HOME first, then
+// $XDG_DATA_HOME/applications and each $XDG_DATA_DIRS/applications dir
+fn mimeapps_list_paths() -> Vec<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            let mut h = PathBuf::from(env::var("HOME").unwrap());
+            h.push(".config");
+            h
+        });
+
+    iter::once(config_home.join("mimeapps.list"))
+        .chain(xdg_applications_dirs().into_iter().map(|dir| dir.join("mimeapps.list")))
+        .collect()
+}
+
+// mimeapps.list lists desktop-file IDs with their `.desktop` suffix
+// intact; strip it so these IDs share a namespace with the ones
+// `desktop_file_id` produces for `apps`
+fn split_ids(value: &str) -> Vec<String> {
+    value.split(';')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.trim_end_matches(".desktop").to_string())
+        .collect()
+}
+
+fn load_mime_associations() -> MimeAssociations {
+    let mut associations = MimeAssociations::default();
+
+    // earlier files take priority: a [Default Applications] entry already
+    // set for a mimetype is kept, while associations accumulate
+    for path in mimeapps_list_paths() {
+        if let Ok(entry) = parse_entry(&path) {
+            for attr in entry.section("Default Applications").attrs() {
+                associations.default.entry(attr.name.to_string()).or_insert_with(|| split_ids(attr.value.unwrap_or("")));
+            }
+            for attr in entry.section("Added Associations").attrs() {
+                associations.added.entry(attr.name.to_string()).or_insert_with(Vec::new).extend(split_ids(attr.value.unwrap_or("")));
+            }
+            for attr in entry.section("Removed Associations").attrs() {
+                associations.removed.entry(attr.name.to_string()).or_insert_with(Vec::new).extend(split_ids(attr.value.unwrap_or("")));
+            }
+        }
+    }
+
+    associations
+}
+
+
+// a single app's launch history, used to compute its frecency score
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct UsageEntry {
+    count: u64,
+    last_launched: u64, // unix epoch seconds
+}
+
+// persistent per-user launch history, keyed by desktop-file ID; unknown
+// fields are ignored so the format stays forward-compatible
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UsageCache {
+    #[serde(default)]
+    entries: HashMap<String, UsageEntry>,
+}
+
+impl UsageCache {
+    // frecency: launch count decayed by how long ago the app was last
+    // launched (count / (1 + days since)), so a frequently-used app not
+    // touched in a while can sink below one launched recently
+    fn score(&self, id: &str, now: u64) -> f64 {
+        match self.entries.get(id) {
+            Some(entry) => {
+                let age_days = now.saturating_sub(entry.last_launched) as f64 / 86400.0;
+                entry.count as f64 / (1.0 + age_days)
+            },
+            None => 0.0,
+        }
+    }
+
+    fn record_launch(&mut self, id: &str, now: u64) {
+        let entry = self.entries.entry(id.to_string()).or_default();
+        entry.count += 1;
+        entry.last_launched = now;
+    }
+}
+
+fn usage_cache_path() -> PathBuf {
+    let cache_home = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            let mut h = PathBuf::from(env::var("HOME").unwrap());
+            h.push(".cache");
+            h
+        });
+
+    cache_home.join("app-launch").join("usage")
+}
+
+fn load_usage_cache(path: &Path) -> UsageCache {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+// writes the cache atomically: a temp file next to `path`, then a rename
+fn save_usage_cache(path: &Path, cache: &UsageCache) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    let serialized = serde_json::to_string(cache)
+        .map_err(io::Error::other)?;
+    std::fs::write(&tmp_path, serialized)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+fn now_unix() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// orders `ids` by frecency, highest first, falling back to alphabetical
+// order for ties and for apps that have never been launched
+fn sort_by_usage(ids: &mut [String], apps: &HashMap<String, Application>, cache: &UsageCache, now: u64) {
+    ids.sort_by(|a, b| {
+        cache.score(b, now).partial_cmp(&cache.score(a, now))
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| apps[a].name.cmp(&apps[b].name))
+    });
+}
+
+// launches `process` detached from app-launch: own session, stdio
+// dropped, and not waited on, so a menu launcher returns immediately
+// instead of blocking for the lifetime of the launched application
+fn spawn_detached(mut process: Command, working_dir: Option<&Path>, sanitize_env: bool) -> io::Result<()> {
+    if sanitize_env {
+        sanitize_environment(&mut process);
+    }
+
+    if let Some(dir) = working_dir {
+        process.current_dir(dir);
+    }
+
+    process.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    unsafe {
+        process.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    process.spawn()?;
+    Ok(())
+}
+
+// path-list variables that an AppImage/Flatpak/Snap runtime commonly
+// rewrites to point into the bundle, and which therefore must not leak
+// into a launched native application
+const SANITIZED_PATH_VARS: &[&str] = &[
+    "PATH",
+    "LD_LIBRARY_PATH",
+    "GST_PLUGIN_SYSTEM_PATH",
+    "GTK_PATH",
+    "PYTHONPATH",
+    "XDG_DATA_DIRS",
+];
+
+// true if app-launch itself appears to be running from inside an
+// AppImage, Flatpak, or Snap
+fn running_in_bundle() -> bool {
+    env::var_os("APPDIR").is_some()
+        || env::var_os("APPIMAGE").is_some()
+        || env::var_os("FLATPAK_ID").is_some()
+        || env::var_os("SNAP").is_some()
+}
+
+// the bundle mount points whose entries should be stripped from
+// inherited path-list variables
+fn bundle_prefixes() -> Vec<PathBuf> {
+    ["APPDIR", "SNAP"].iter()
+        .filter_map(|var| env::var_os(var))
+        .map(PathBuf::from)
+        // Flatpak has no single mount-point variable; /app is its
+        // well-known runtime prefix
+        .chain(env::var_os("FLATPAK_ID").is_some().then(|| PathBuf::from("/app")))
+        .collect()
+}
+
+// strips bundle-injected entries out of `name` on `process`'s
+// environment, deduplicating what's left and unsetting the variable
+// entirely rather than exporting it empty
+fn sanitize_path_var(process: &mut Command, name: &str, bundle_prefixes: &[PathBuf]) {
+    let value = match env::var_os(name) {
+        Some(v) => v,
+        None => return,
+    };
+
+    let mut seen = std::collections::HashSet::new();
+    let cleaned: Vec<PathBuf> = env::split_paths(&value)
+        .filter(|p| !bundle_prefixes.iter().any(|prefix| p.starts_with(prefix)))
+        .filter(|p| seen.insert(p.clone()))
+        .collect();
+
+    if cleaned.iter().all(|p| p.as_os_str().is_empty()) {
+        process.env_remove(name);
+    } else if let Ok(joined) = env::join_paths(&cleaned) {
+        process.env(name, joined);
+    }
+}
+
+// an always-on fixup (unless opted out of) that strips bundle-specific
+// path entries before they get passed down to the launched application
+fn sanitize_environment(process: &mut Command) {
+    if !running_in_bundle() {
+        return;
+    }
+
+    let prefixes = bundle_prefixes();
+    for var in SANITIZED_PATH_VARS {
+        sanitize_path_var(process, var, &prefixes);
+    }
+}
 
 fn main() {
     let matches = clap::App::new(env!("CARGO_PKG_NAME"))
@@ -121,10 +629,26 @@ application using a menu of their choice, such as dmenu")
             .required(true)
             .index(1)
             )
+        .arg(clap::Arg::with_name("mime")
+            .short("m")
+            .long("mime")
+            .value_name("TYPE")
+            .help("only list applications that handle this MIME type, ordered by default association")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("open")
+            .short("o")
+            .long("open")
+            .value_name("FILE")
+            .help("open FILE with the chosen application; implies --mime from FILE's type unless --mime is also given")
+            .takes_value(true))
+        .arg(clap::Arg::with_name("no-sanitize-env")
+            .long("no-sanitize-env")
+            .help("don't strip AppImage/Flatpak/Snap bundle paths from the launched application's environment"))
         .arg(clap::Arg::with_name("searchdirs")
             .help(
-"the directories to be searched, defaults to
-/usr/share/applications and ~/.local/share/applications")
+"the directories to be searched, defaults to the XDG Base
+Directory applications dirs ($XDG_DATA_HOME/applications and
+$XDG_DATA_DIRS/applications)")
             .index(2)
             .multiple(true)
             .required(false)
@@ -135,40 +659,93 @@ application using a menu of their choice, such as dmenu")
         .split_whitespace()
         .collect();
 
-    let apps_repos: Vec<io::Result<Vec<Application>>> = match matches.values_of("searchdirs") {
+    let apps_repos: Vec<io::Result<Vec<(String, Application)>>> = match matches.values_of("searchdirs") {
         Some(searchdirs) => {
             searchdirs.map(get_desktop_apps)
                 .collect()
         },
         None => {
-            let mut h = PathBuf::new(); // doesn't like ~/.local/share/applications for some reason
-            h.push(env::var("HOME").unwrap());
-            h.push(".local");
-            h.push("share");
-            h.push("applications");
+            xdg_applications_dirs().into_iter()
+                .map(get_desktop_apps)
+                .collect()
+        }
+    };
 
-            vec![
-                get_desktop_apps("/usr/share/applications"),
-                get_desktop_apps(h),
-            ]
+    let apps = merge_apps(apps_repos);
+
+    let usage_cache_path = usage_cache_path();
+    let mut usage_cache = load_usage_cache(&usage_cache_path);
+    let now = now_unix();
+
+    let mime_filter = matches.value_of("mime")
+        .map(String::from)
+        .or_else(|| matches.value_of("open")
+            .and_then(|file| mime_guess::from_path(file).first_raw())
+            .map(String::from));
+
+    // in mime mode, IDs are ordered default-association-first so the
+    // default can be pre-selected/highlighted; everything else is
+    // ordered by frecency (falling back to alphabetical)
+    let order: Vec<String> = match &mime_filter {
+        Some(mime) => {
+            let associations = load_mime_associations();
+            let mut seen = std::collections::HashSet::new();
+
+            let mut ids: Vec<String> = associations.handlers_for(mime).into_iter()
+                .filter(|id| apps.contains_key(id))
+                .filter(|id| seen.insert(id.clone()))
+                .collect();
+
+            let mut rest: Vec<String> = apps.iter()
+                .filter(|(id, app)| app.body.mime_types.contains(mime) && !seen.contains(*id))
+                .map(|(id, _)| (*id).clone())
+                .collect();
+            sort_by_usage(&mut rest, &apps, &usage_cache, now);
+
+            ids.extend(rest);
+            ids
+        },
+        None => {
+            let mut ids: Vec<String> = apps.keys().cloned().collect();
+            sort_by_usage(&mut ids, &apps, &usage_cache, now);
+            ids
         }
     };
 
-    let apps_map: HashMap<_, _> = apps_repos.into_iter()
-        .filter_map(Result::ok)
-        .flatten()
-        .map(|i| (i.name, i.body))
-        .collect();
+    // Name alone isn't a safe menu key: distinct desktop-file IDs can
+    // share a Name (that's exactly what merge_apps's ID-level dedup is
+    // for), so disambiguate colliding labels with the ID before using
+    // them as the lookup key back to a body
+    let mut name_counts: HashMap<&str, usize> = HashMap::new();
+    for app in apps.values() {
+        *name_counts.entry(app.name.as_str()).or_insert(0) += 1;
+    }
 
-    let mut app_names: Vec<&str> = apps_map.iter()
-        .map(|i| &**i.0)
+    let id_to_label: HashMap<String, String> = apps.iter()
+        .map(|(id, app)| {
+            let label = if name_counts[app.name.as_str()] > 1 {
+                format!("{} ({})", app.name, id)
+            } else {
+                app.name.clone()
+            };
+            (id.clone(), label)
+        })
         .collect();
 
-    app_names.sort();
+    let app_names: Vec<String> = order.iter().map(|id| id_to_label[id].clone()).collect();
+
+    let mut label_to_id: HashMap<String, String> = HashMap::new();
+    let apps_map: HashMap<String, (String, ApplicationBody)> = apps.into_iter()
+        .map(|(id, app)| {
+            let label = id_to_label[&id].clone();
+            label_to_id.insert(label.clone(), id);
+            (label, (app.name, app.body))
+        })
+        .collect();
 
     let newlines = iter::repeat("\n").take(app_names.len());
 
-    let menu_process_stdin = app_names.into_iter().interleave(newlines).collect::<Vec<&str>>().concat();
+    let menu_process_stdin = app_names.iter().map(String::as_str).interleave(newlines).collect::<Vec<&str>>().concat();
 
 
     let mut menu_process = Command::new(&menu_program[0]);
@@ -214,7 +791,10 @@ application using a menu of their choice, such as dmenu")
 
     println!("chosen program: {}", selected_program);
 
-    let program = &apps_map[&selected_program];
+    let (name, program) = &apps_map[&selected_program];
+    let targets: Vec<&str> = matches.value_of("open").into_iter().collect();
+    let exec = program.expanded_exec(name, &targets);
+    let sanitize_env = !matches.is_present("no-sanitize-env");
 
     if program.term {
         let terminal_emulator = {
@@ -232,32 +812,33 @@ application using a menu of their choice, such as dmenu")
 
         let mut process = Command::new(&terminal_emulator);
         process.arg("-e");
-        for i in program.exec.iter() {
+        for i in exec.iter() {
             process.arg(i);
         }
 
-        match process.output() {
-            Err(e) => {
-                eprintln!("error when executing '{} -e {}': {}", &terminal_emulator, program.exec.join(" "), e);
-                exit(1);
-            },
-            _ => ()
+        if let Err(e) = spawn_detached(process, program.working_dir.as_deref(), sanitize_env) {
+            eprintln!("error when executing '{} -e {}': {}", &terminal_emulator, exec.join(" "), e);
+            exit(1);
         }
-        
-        
+
+
     }
     else {
-        let mut process = Command::new(&program.exec[0]);
-        for i in 1..program.exec.len() {
-            process.arg(&program.exec[i]);
+        let mut process = Command::new(&exec[0]);
+        for i in 1..exec.len() {
+            process.arg(&exec[i]);
         }
 
-        match process.output() {
-            Err(e) => {
-                eprintln!("error when executing '{}': {}", program.exec.join(" "), e);
-                exit(1);
-            },
-            _ => ()
+        if let Err(e) = spawn_detached(process, program.working_dir.as_deref(), sanitize_env) {
+            eprintln!("error when executing '{}': {}", exec.join(" "), e);
+            exit(1);
+        }
+    }
+
+    if let Some(id) = label_to_id.get(&selected_program).cloned() {
+        usage_cache.record_launch(&id, now);
+        if let Err(e) = save_usage_cache(&usage_cache_path, &usage_cache) {
+            eprintln!("warning: failed to update usage cache: {}", e);
         }
     }
 }